@@ -11,33 +11,97 @@ pub struct Step {
     args:   Vec<String>
 }
 
+/// One entry of a clang "compilation database", as consumed by clangd,
+/// clang-tidy and include-what-you-use.
+#[derive(serde::Serialize)]
+struct CompileCommand {
+    directory:  String,
+    file:       String,
+    arguments:  Vec<String>,
+    output:     String,
+}
+
 pub struct Make {
     artifact:   Artifact,
     steps:      Vec<Step>,
     cc:         String,
     cflags:     Vec<String>,
     lflags:     Vec<String>,
+    objects:    Vec<String>,
+    jobserver:  jobserver::Client,
+    target:     Option<String>,
+    sandbox:        bool,
+    sandbox_roots:  Vec<String>,
+    compile_commands: Vec<CompileCommand>,
 }
 
 impl Make {
+    /// Looks up `<VAR>_<triple>` first, then `<VAR>_<triple-with-underscores>`
+    /// (a triple's dashes can't be set via plain shell `export`/assignment
+    /// syntax, so cc-rs/rustc's bootstrap `cc.rs` accept the underscored form
+    /// too), then falls back to plain `<VAR>`, then `default`.
+    fn select_compiler(var: &str, default: &str, target: &Option<String>) -> String {
+        if let Some(triple) = target {
+            if let Ok(v) = std::env::var(format!("{}_{}", var, triple)) {
+                return v;
+            }
+            if let Ok(v) = std::env::var(format!("{}_{}", var, triple.replace('-', "_"))) {
+                return v;
+            }
+        }
+        std::env::var(var).unwrap_or_else(|_| default.to_string())
+    }
+
+    /// Picks the archiver for a `StaticLib`: an explicit `AR` wins, otherwise
+    /// a cross build uses `<triple>-ar`, otherwise plain `ar`.
+    fn select_ar(target: &Option<String>) -> String {
+        if let Ok(ar) = std::env::var("AR") {
+            return ar;
+        }
+        match target {
+            Some(triple) => format!("{}-ar", triple),
+            None => "ar".to_string(),
+        }
+    }
+
     pub fn new(mut project: Project, artifact: Artifact) -> Self {
 
         let mut lflags = Vec::new();
         let mut cflags = Vec::new();
 
-        let mut cc = std::env::var("CC").unwrap_or("clang".to_string());
+        let target = project.target.clone();
+
+        let mut cc = Self::select_compiler("CC", "clang", &target);
 
         if let Some(std) = project.std {
             cflags.push(format!("-std={}", std));
             if std.contains("c++") {
-                cc = std::env::var("CXX").unwrap_or("clang++".to_string());
+                cc = Self::select_compiler("CXX", "clang++", &target);
             }
         }
 
+        if let Some(triple) = &target {
+            cflags.push("-target".into());
+            cflags.push(triple.clone());
+            lflags.push("-target".into());
+            lflags.push(triple.clone());
+        }
+        if let Some(sysroot) = &project.sysroot {
+            cflags.push("--sysroot".into());
+            cflags.push(sysroot.clone());
+            lflags.push("--sysroot".into());
+            lflags.push(sysroot.clone());
+        }
+
+        // declared include roots, mirrored into the sandbox mount namespace
+        // when project.sandbox is set. "." is the crate root itself.
+        let mut sandbox_roots: Vec<String> = vec![".".to_string()];
+
         if let Some(cincs) = &project.cincludes {
             for cinc in cincs {
                 cflags.push("-I".into());
                 cflags.push(cinc.clone());
+                sandbox_roots.push(cinc.clone());
             }
         }
 
@@ -52,6 +116,9 @@ impl Make {
                 let flags = String::from_utf8_lossy(&flags.stdout);
                 let flags = flags.split_whitespace();
                 for flag in flags {
+                    if let Some(dir) = flag.strip_prefix("-I") {
+                        sandbox_roots.push(dir.to_string());
+                    }
                     cflags.push(flag.to_string());
                 }
 
@@ -86,6 +153,21 @@ impl Make {
             lflags.extend(plflags.clone());
         }
 
+        // inherit a jobserver from a parent make/zz if one was passed down via MAKEFLAGS,
+        // otherwise become the server ourselves so nested invocations cooperate too.
+        let jobserver = match unsafe { jobserver::Client::from_env() } {
+            Some(client) => client,
+            None => {
+                let parallelism = std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1);
+                // we always implicitly hold one token ourselves, so the pipe only
+                // needs to be preloaded with the remaining N-1.
+                jobserver::Client::new(parallelism.saturating_sub(1))
+                    .expect("failed to create jobserver")
+            }
+        };
+
         let mut m = Make {
             cc,
             artifact,
@@ -93,6 +175,12 @@ impl Make {
             lflags,
             cflags,
             steps: Vec::new(),
+            jobserver,
+            target,
+            sandbox: project.sandbox,
+            sandbox_roots,
+            compile_commands: Vec::new(),
+            objects: Vec::new(),
         };
 
         if let Some(c) = cobjects {
@@ -105,6 +193,44 @@ impl Make {
     }
 
 
+    /// Parses a clang-emitted `-MF` dependency file (`target.o: dep1 dep2 \`,
+    /// with backslash line-continuations and backslash-escaped spaces inside
+    /// paths) and returns every listed dependency, not including the target
+    /// itself. Returns `None` if the file is missing or empty, which callers
+    /// should treat as "rebuild unconditionally".
+    fn parse_depfile(path: &Path) -> Option<HashSet<PathBuf>> {
+        let raw = std::fs::read_to_string(path).ok()?;
+        let raw = raw.replace("\\\n", " ");
+
+        let mut tokens = Vec::new();
+        let mut cur = String::new();
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&' ') => {
+                    cur.push(' ');
+                    chars.next();
+                }
+                c if c.is_whitespace() => {
+                    if !cur.is_empty() {
+                        tokens.push(std::mem::take(&mut cur));
+                    }
+                }
+                c => cur.push(c),
+            }
+        }
+        if !cur.is_empty() {
+            tokens.push(cur);
+        }
+
+        if tokens.is_empty() {
+            return None;
+        }
+
+        // first token is "target.o:", everything after it is a dependency
+        Some(tokens.into_iter().skip(1).map(PathBuf::from).collect())
+    }
+
     fn is_dirty(&self, sources: &HashSet<PathBuf>, target: &str) -> bool {
         let itarget = match std::fs::metadata(target) {
             Ok(v)  => v,
@@ -113,7 +239,13 @@ impl Make {
         let itarget = itarget.modified().expect(&format!("cannot stat {}", target));
 
         for source in sources {
-            let isource = std::fs::metadata(source).expect(&format!("cannot stat {:?}", source));
+            let isource = match std::fs::metadata(source) {
+                Ok(v) => v,
+                // a dependency that no longer exists (e.g. a stale .d entry
+                // for a header that was since deleted or renamed) can't be
+                // trusted either way: force a rebuild instead of panicking.
+                Err(_) => return true,
+            };
 
             let isource = isource.modified().expect(&format!("cannot stat {:?}", source));
 
@@ -124,6 +256,26 @@ impl Make {
         return false;
     }
 
+    /// Records one clang invocation into the compilation database, whether
+    /// or not it ended up being skipped as up to date, so editor tooling
+    /// sees the whole project.
+    fn record_compile_command(&mut self, file: &str, args: &[String], outp: &str) {
+        let directory = std::env::current_dir()
+            .expect("cannot get cwd")
+            .to_string_lossy()
+            .into_owned();
+
+        let mut arguments = vec![self.cc.clone()];
+        arguments.extend(args.iter().cloned());
+
+        self.compile_commands.push(CompileCommand {
+            directory,
+            file: file.to_string(),
+            arguments,
+            output: outp.to_string(),
+        });
+    }
+
     pub fn cobject(&mut self, inp: &Path) {
 
         let mut args = self.cflags.clone();
@@ -139,16 +291,35 @@ impl Make {
 
         args.push(outp.clone());
 
+        // -MF path is appended after the hash above so it never perturbs the
+        // object's cache name.
+        let depfile = format!("{}.d", outp);
+        args.push("-MMD".to_string());
+        args.push("-MF".to_string());
+        args.push(depfile.clone());
+
         let mut sources = HashSet::new();
         sources.insert(inp.into());
-        if self.is_dirty(&sources, &outp) {
+
+        let dirty = match Self::parse_depfile(Path::new(&depfile)) {
+            Some(deps) => {
+                sources.extend(deps);
+                self.is_dirty(&sources, &outp)
+            }
+            // no (or unparsable) .d yet: first build, or a build that got interrupted
+            None => true,
+        };
+
+        self.record_compile_command(&inp.to_string_lossy(), &args, &outp);
+
+        if dirty {
             self.steps.push(Step{
                 source: inp.into(),
                 args,
             });
         }
 
-        self.lflags.insert(0, outp);
+        self.objects.push(outp);
     }
 
     pub fn build(&mut self, cf: &super::emitter::CFile) {
@@ -171,40 +342,185 @@ impl Make {
         let outp = format!("./target/zz/{}_{:x}.o", cf.name, hash);
         args.push(outp.clone());
 
-        if self.is_dirty(&cf.sources, &outp) {
+        let depfile = format!("{}.d", outp);
+        args.push("-MMD".to_string());
+        args.push("-MF".to_string());
+        args.push(depfile.clone());
+
+        let mut sources = cf.sources.clone();
+
+        let dirty = match Self::parse_depfile(Path::new(&depfile)) {
+            Some(deps) => {
+                sources.extend(deps);
+                self.is_dirty(&sources, &outp)
+            }
+            None => true,
+        };
+
+        self.record_compile_command(&cf.filepath, &args, &outp);
+
+        if dirty {
             self.steps.push(Step{
                 source: Path::new(&cf.filepath).into(),
                 args,
             });
         }
-        self.lflags.insert(0, outp);
+        self.objects.push(outp);
     }
 
 
     pub fn link(mut self) {
-        use rayon::prelude::*;
         use std::sync::{Arc, Mutex};
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
         let pb = Arc::new(Mutex::new(pbr::ProgressBar::new(self.steps.len() as u64)));
-        self.steps.par_iter().for_each(|step|{
-            pb.lock().unwrap().message(&format!("{} {:?} ", self.cc, step.source));
+        let failed: Mutex<Option<i32>> = Mutex::new(None);
+        let next_step = AtomicUsize::new(0);
+
+        // a handful of worker threads pulling from the step list, not one
+        // thread per step: with thousands of translation units the latter
+        // means thousands of OS threads sitting blocked on the jobserver
+        // pipe just to get scheduled, which is real memory/thread-table
+        // pressure the old rayon pool didn't have.
+        //
+        // when sandboxing is on, `sandbox::wrap`'s `pre_exec` closure runs
+        // after fork() in a process that would otherwise still have other
+        // worker threads alive; pre_exec must be async-signal-safe, and a
+        // fork() racing another thread's heap allocation can wedge the
+        // child forever. Serialize to a single in-flight compile so there's
+        // never a concurrent fork to race.
+        let workers = if self.sandbox {
+            1
+        } else {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        }.min(self.steps.len().max(1));
+
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| {
+                let pb = Arc::clone(&pb);
+                let failed = &failed;
+                let jobserver = &self.jobserver;
+                let cc = &self.cc;
+                let sandbox = self.sandbox;
+                let sandbox_roots = &self.sandbox_roots;
+                let steps = &self.steps;
+                let next_step = &next_step;
+                scope.spawn(move || {
+                    loop {
+                        let i = next_step.fetch_add(1, Ordering::SeqCst);
+                        let step = match steps.get(i) {
+                            Some(step) => step,
+                            None => break,
+                        };
+
+                        // every Make implicitly owns one token without ever reading it off
+                        // the pipe, so the very first step spends that implicit token
+                        // directly; every other step blocks on `acquire()` as usual. The
+                        // token is returned to the pipe on every exit path (including
+                        // panics) when `token` drops.
+                        let token = if i == 0 {
+                            None
+                        } else {
+                            Some(jobserver.acquire().expect("failed to acquire jobserver token"))
+                        };
+
+                        pb.lock().unwrap().message(&format!("{} {:?} ", cc, step.source));
+
+                        let mut cmd = Command::new(cc);
+                        cmd.args(&step.args);
+                        jobserver.configure(&mut cmd);
+                        let sandbox_root = if sandbox {
+                            Some(sandbox::wrap(&mut cmd, sandbox_roots.clone(), "./target".into()))
+                        } else {
+                            None
+                        };
+                        // a failure to `unshare`/mount inside `sandbox::wrap`'s pre_exec
+                        // (e.g. unprivileged user namespaces disabled) surfaces here as a
+                        // spawn error, not a process exit status; treat it the same as a
+                        // failed compile instead of panicking, and still clean up the
+                        // staging dir.
+                        let status = cmd.status();
+                        if let Some(root) = sandbox_root {
+                            let _ = std::fs::remove_dir_all(root);
+                        }
+                        let success = match &status {
+                            Ok(status) => status.success(),
+                            Err(e) => {
+                                error!("error executing {} for {:?}: {}", cc, step.source, e);
+                                false
+                            }
+                        };
+                        if !success {
+                            if status.is_ok() {
+                                error!("error compiling {:?}", step.source);
+                            }
+                            let mut failed = failed.lock().unwrap();
+                            if failed.is_none() {
+                                *failed = Some(status.ok().and_then(|s| s.code()).unwrap_or(3));
+                            }
+                        }
+                        pb.lock().unwrap().inc();
+                        drop(token);
+                    }
+                })
+            }).collect();
+
+            for handle in handles {
+                handle.join().expect("compile thread panicked");
+            }
+        });
+
+        // written unconditionally, even on a failed build below: clangd/
+        // clang-tidy/include-what-you-use want to understand the project on
+        // the most common occasion they're consulted, a build that doesn't
+        // currently compile.
+        self.write_compile_commands();
+
+        if let Some(code) = *failed.lock().unwrap() {
+            std::process::exit(code);
+        }
 
-            let status = Command::new(&self.cc)
-                .args(&step.args)
+        if let super::project::ArtifactType::StaticLib = self.artifact.typ {
+            let ar = Self::select_ar(&self.target);
+            let archive = format!("./target/{}.a", self.artifact.name);
+
+            pb.lock().unwrap().message(&format!("[WORK] ar [StaticLib] {}", self.artifact.name));
+            debug!("{:?}", self.objects);
+
+            let status = Command::new(&ar)
+                .arg("rcs")
+                .arg(&archive)
+                .args(&self.objects)
                 .status()
-                .expect("failed to execute cc");
+                .expect("failed to execute ar");
             if !status.success() {
-                error!("error compiling {:?}", step.source);
                 std::process::exit(status.code().unwrap_or(3));
             }
-            pb.lock().unwrap().inc();
-        });
+
+            pb.lock().unwrap().finish_print("done archiving");
+            return;
+        }
+
+        // objects must come first on the link line; everything already in
+        // self.lflags is -l/-L and other real link flags.
+        let mut lflags = std::mem::take(&mut self.objects);
+        lflags.append(&mut self.lflags);
+        self.lflags = lflags;
 
         match self.artifact.typ {
             super::project::ArtifactType::Lib => {
-                self.lflags.push("-shared".into());
+                let (shared_flag, ext) = match &self.target {
+                    Some(triple) if triple.contains("apple") || triple.contains("darwin") =>
+                        ("-dynamiclib", "dylib"),
+                    Some(triple) if triple.contains("windows") =>
+                        ("-shared", "dll"),
+                    _ => ("-shared", "so"),
+                };
+                self.lflags.push(shared_flag.into());
                 self.lflags.push("-o".into());
-                self.lflags.push(format!("./target/{}.so", self.artifact.name));
+                self.lflags.push(format!("./target/{}.{}", self.artifact.name, ext));
             },
             super::project::ArtifactType::Exe => {
                 self.lflags.push("-o".into());
@@ -217,6 +533,7 @@ impl Make {
             super::project::ArtifactType::Header  => {
                 panic!("cannot link header yet");
             }
+            super::project::ArtifactType::StaticLib => unreachable!("handled above"),
         }
         self.lflags.push("-fvisibility=hidden".into());
 
@@ -233,4 +550,263 @@ impl Make {
 
         pb.lock().unwrap().finish_print("done linking");
     }
+
+    fn write_compile_commands(&self) {
+        let compile_commands = serde_json::to_string_pretty(&self.compile_commands)
+            .expect("failed to serialize compile_commands.json");
+        std::fs::write("./target/compile_commands.json", compile_commands)
+            .expect("failed to write compile_commands.json");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_depfile(contents: &str, f: impl FnOnce(&Path) -> Option<HashSet<PathBuf>>) -> Option<HashSet<PathBuf>> {
+        let path = std::env::temp_dir().join(format!(
+            "zz-test-depfile-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id(),
+        ));
+        std::fs::write(&path, contents).expect("failed to write test depfile");
+        let result = f(&path);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    fn deps(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn single_dependency() {
+        let result = with_depfile("foo.o: bar.h\n", |p| Make::parse_depfile(p));
+        assert_eq!(result, Some(deps(&["bar.h"])));
+    }
+
+    #[test]
+    fn multiple_deps_with_line_continuation() {
+        let result = with_depfile("foo.o: bar.h \\\n baz.h qux.h\n", |p| Make::parse_depfile(p));
+        assert_eq!(result, Some(deps(&["bar.h", "baz.h", "qux.h"])));
+    }
+
+    #[test]
+    fn escaped_spaces_in_paths() {
+        let result = with_depfile("foo.o: dir\\ with\\ spaces/bar.h\n", |p| Make::parse_depfile(p));
+        assert_eq!(result, Some(deps(&["dir with spaces/bar.h"])));
+    }
+
+    #[test]
+    fn empty_file_is_none() {
+        let result = with_depfile("", |p| Make::parse_depfile(p));
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn missing_file_is_none() {
+        let path = std::env::temp_dir().join("zz-test-depfile-does-not-exist");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(Make::parse_depfile(&path), None);
+    }
+
+    // select_compiler/select_ar read process-global env vars, so the tests
+    // below serialize on this lock rather than risk one test observing
+    // another's in-flight var.
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn compiler_falls_back_to_default() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("CC");
+        assert_eq!(Make::select_compiler("CC", "clang", &None), "clang");
+    }
+
+    #[test]
+    fn compiler_uses_plain_var() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("CC", "plain-cc");
+        let result = Make::select_compiler("CC", "clang", &None);
+        std::env::remove_var("CC");
+        assert_eq!(result, "plain-cc");
+    }
+
+    #[test]
+    fn compiler_prefers_triple_specific_var_over_plain() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("CC", "plain-cc");
+        std::env::set_var("CC_x86_64-unit-test", "triple-cc");
+        let result = Make::select_compiler("CC", "clang", &Some("x86_64-unit-test".to_string()));
+        std::env::remove_var("CC");
+        std::env::remove_var("CC_x86_64-unit-test");
+        assert_eq!(result, "triple-cc");
+    }
+
+    #[test]
+    fn compiler_falls_back_to_underscore_normalized_triple_var() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("CC");
+        std::env::remove_var("CC_x86_64-unknown-unit-test");
+        std::env::set_var("CC_x86_64_unknown_unit_test", "underscored-cc");
+        let result = Make::select_compiler("CC", "clang", &Some("x86_64-unknown-unit-test".to_string()));
+        std::env::remove_var("CC_x86_64_unknown_unit_test");
+        assert_eq!(result, "underscored-cc");
+    }
+
+    #[test]
+    fn ar_falls_back_to_plain_ar() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("AR");
+        assert_eq!(Make::select_ar(&None), "ar");
+    }
+
+    #[test]
+    fn ar_uses_triple_prefixed_ar_for_cross_target() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("AR");
+        let result = Make::select_ar(&Some("x86_64-unit-test".to_string()));
+        assert_eq!(result, "x86_64-unit-test-ar");
+    }
+
+    #[test]
+    fn ar_env_var_overrides_triple() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("AR", "custom-ar");
+        let result = Make::select_ar(&Some("x86_64-unit-test".to_string()));
+        std::env::remove_var("AR");
+        assert_eq!(result, "custom-ar");
+    }
+}
+
+/// Namespace-based sandboxing for individual compile steps. When enabled via
+/// `project.sandbox`, each compiler invocation runs in a fresh user+mount
+/// namespace where only the declared include roots (plus the crate root,
+/// read-only) and a writable bind of the host's `./target/` are visible on
+/// top of an empty tmpfs root, so an
+/// `#include` that isn't covered by `cincludes`/`pkgconfig` fails with ENOENT
+/// instead of silently resolving against the host filesystem. This mirrors
+/// what rebel-runner's namespace runner does for arbitrary build steps.
+mod sandbox {
+    use std::ffi::CString;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// Arranges for `cmd` to unshare its own user+mount namespace right
+    /// before exec, with only `readonly` (read-only) and `target_dir`
+    /// (writable) bind-mounted into a tmpfs root. Returns the staging directory the
+    /// caller should `remove_dir_all` once `cmd` has exited.
+    pub fn wrap(cmd: &mut Command, readonly: Vec<String>, target_dir: PathBuf) -> PathBuf {
+        use std::os::unix::process::CommandExt;
+
+        let readonly: Vec<PathBuf> = readonly.iter()
+            .filter_map(|p| std::fs::canonicalize(p).ok())
+            .collect();
+        let target_dir = std::fs::canonicalize(&target_dir).unwrap_or(target_dir);
+
+        // staged under the system tmpdir rather than under target_dir (or
+        // any declared include root): otherwise the root we bind-mount
+        // everything into would end up containing itself, and every build
+        // would leave a stray directory behind inside the project's own
+        // ./target/.
+        let new_root = std::env::temp_dir().join(format!("zz-sandbox-{}", std::process::id()));
+        let old_root = new_root.join(".old_root");
+        std::fs::create_dir_all(&old_root).expect("failed to create sandbox root");
+
+        // the crate root is one of `readonly` and gets bind-mounted at its
+        // own mirrored absolute path under `new_root`, not at the new "/";
+        // `cc` is still invoked with the relative paths it always gets
+        // ("-I", ".", "./target/..."), so the child must chdir to that same
+        // mirrored path after pivot_root, not to "/".
+        let cwd = std::env::current_dir()
+            .ok()
+            .and_then(|p| std::fs::canonicalize(p).ok())
+            .unwrap_or_else(|| PathBuf::from("/"));
+
+        unsafe {
+            cmd.pre_exec({
+                let new_root = new_root.clone();
+                move || setup(&readonly, &target_dir, &cwd, &new_root, &old_root)
+            });
+        }
+
+        new_root
+    }
+
+    fn setup(readonly: &[PathBuf], target_dir: &Path, cwd: &Path, new_root: &Path, old_root: &Path) -> std::io::Result<()> {
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+
+        if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // map our own uid/gid to 0 inside the namespace so we keep permission to perform the
+        // bind mounts below from inside the new user namespace.
+        std::fs::write("/proc/self/setgroups", b"deny")?;
+        std::fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+        std::fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+
+        // detach from the host's mount propagation so nothing we do here
+        // leaks back out.
+        mount(None, Path::new("/"), None, libc::MS_REC | libc::MS_PRIVATE)?;
+
+        mount(Some("tmpfs"), new_root, Some("tmpfs"), 0)?;
+
+        for dir in readonly {
+            bind(dir, &new_root.join(dir.strip_prefix("/").unwrap_or(dir)), true)?;
+        }
+        bind(target_dir, &new_root.join(target_dir.strip_prefix("/").unwrap_or(target_dir)), false)?;
+
+        let new_root_c = to_cstring(new_root);
+        let old_root_c = to_cstring(old_root);
+        if unsafe { libc::syscall(libc::SYS_pivot_root, new_root_c.as_ptr(), old_root_c.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mirrored_cwd = Path::new("/").join(cwd.strip_prefix("/").unwrap_or(cwd));
+        std::env::set_current_dir(&mirrored_cwd)?;
+        umount_lazy(Path::new("/.old_root"))?;
+
+        Ok(())
+    }
+
+    fn bind(src: &Path, dst: &Path, readonly: bool) -> std::io::Result<()> {
+        std::fs::create_dir_all(dst)?;
+        mount(Some(src.to_str().expect("non-utf8 include path")), dst, None, libc::MS_BIND)?;
+        if readonly {
+            mount(None, dst, None, libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY)?;
+        }
+        Ok(())
+    }
+
+    fn mount(src: Option<&str>, target: &Path, fstype: Option<&str>, flags: libc::c_ulong) -> std::io::Result<()> {
+        let src_c = src.map(|s| CString::new(s).unwrap());
+        let fstype_c = fstype.map(|s| CString::new(s).unwrap());
+        let target_c = to_cstring(target);
+
+        let rc = unsafe {
+            libc::mount(
+                src_c.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                target_c.as_ptr(),
+                fstype_c.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn umount_lazy(target: &Path) -> std::io::Result<()> {
+        let target_c = to_cstring(target);
+        if unsafe { libc::umount2(target_c.as_ptr(), libc::MNT_DETACH) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn to_cstring(p: &Path) -> CString {
+        CString::new(p.as_os_str().to_string_lossy().as_bytes()).expect("path contains NUL")
+    }
 }